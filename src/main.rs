@@ -1,12 +1,11 @@
-use std::io::BufRead;
-use std::net::TcpListener;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use clap::Parser;
 use futures::StreamExt;
 use k8s_openapi::api::core::v1::{
-    EnvVar, PersistentVolumeClaim, PersistentVolumeClaimVolumeSource, Pod, Volume, VolumeMount,
+    EnvVar, PersistentVolumeClaim, PersistentVolumeClaimSpec, PersistentVolumeClaimVolumeSource,
+    Pod, TypedLocalObjectReference, Volume, VolumeMount,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use k8s_openapi::Metadata;
@@ -15,10 +14,15 @@ use kube::core::PartialObjectMetaExt;
 use kube::runtime::conditions::is_deleted;
 use kube::runtime::wait::{await_condition, Condition};
 use log::*;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
 
 const LABEL_KEY: &str = "pv-inspect";
 const LABEL_DELETE: &str = "0";
+/// Annotation patched onto the inspector pod every [`HEARTBEAT_INTERVAL`] for as long as a
+/// session is alive, so that `--cleanup` can tell a live session from an abandoned pod.
+const HEARTBEAT_ANNOTATION: &str = "pv-inspect/heartbeat";
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
 /// Mount a PVC on a new pod, shell into it, and mount it (via SSHFS) if desired.
 #[derive(Parser)]
@@ -26,8 +30,14 @@ const LABEL_DELETE: &str = "0";
 struct Flags {
     #[clap(long, short, default_value = "default")]
     namespace: String,
-    /// Name of the PVC to inspect. If not provided, a list will be shown.
-    name: Option<String>,
+    /// Name(s) of the PVC(s) to inspect. If none are provided, a list will be shown. When several
+    /// are given, each is mounted under a distinct /data/<name> subdirectory; use --mount for
+    /// custom paths.
+    names: Vec<String>,
+    /// Mount a claim at a specific path inside the pod, e.g. `--mount pvc-a:/data/a`. May be
+    /// repeated. Cannot be combined with positional PVC names.
+    #[clap(long = "mount", value_parser = parse_mount)]
+    mounts: Vec<(String, PathBuf)>,
     #[clap(long, short)]
     mountpoint: Option<PathBuf>,
     /// Mount the volume in read/write mode rather than read only.
@@ -42,6 +52,35 @@ struct Flags {
     /// Age in minutes to cleanup pods
     #[clap(long,default_value_t=4*60)]
     cleanup_min: u64,
+    /// Maximum time to wait for the inspector pod to become ready.
+    #[clap(long, default_value = "2m")]
+    ready_timeout: humantime::Duration,
+    /// Maximum time to wait for a pod to be deleted.
+    #[clap(long, default_value = "1m")]
+    delete_timeout: humantime::Duration,
+    /// Inspect a snapshot-based clone of the volume instead of mounting it directly. Use this to
+    /// safely inspect a ReadWriteOnce volume that is already mounted by another pod.
+    #[clap(long)]
+    clone: bool,
+    /// Clone from this VolumeSnapshot rather than from the live PVC (implies --clone).
+    #[clap(long)]
+    from_snapshot: Option<String>,
+    /// Export the claim's mount path (/data unless set with --mount) as a gzipped tar to this
+    /// destination instead of opening an interactive shell. Accepts a local path or an
+    /// `s3://bucket/key` URL.
+    #[clap(long)]
+    export: Option<String>,
+    /// Custom endpoint for `--export`ing to an S3-compatible object store.
+    #[clap(long)]
+    s3_endpoint: Option<String>,
+}
+
+/// Parse a `--mount` value of the form `claim:/path`.
+fn parse_mount(s: &str) -> anyhow::Result<(String, PathBuf)> {
+    let (claim, path) = s
+        .split_once(':')
+        .with_context(|| format!("Invalid --mount {s:?}, expected claim:/path"))?;
+    Ok((claim.to_owned(), PathBuf::from(path)))
 }
 
 #[derive(tabled::Tabled)]
@@ -72,14 +111,28 @@ async fn main_impl() -> anyhow::Result<()> {
             .items;
         let now = chrono::Utc::now();
         let limit = chrono::Duration::minutes(args.cleanup_min as i64);
+        let heartbeat_limit = chrono::Duration::from_std(HEARTBEAT_INTERVAL * 2).unwrap();
         pods_list.retain(|pod| {
-            pod.metadata
-                .creation_timestamp
+            let marked_for_delete = pod.metadata.labels.as_ref().map_or(false, |labels| {
+                labels.get(LABEL_KEY).map(|l| l.as_str()) == Some(LABEL_DELETE)
+            });
+            let heartbeat = pod
+                .metadata
+                .annotations
                 .as_ref()
-                .map_or(false, |t| now - t.0 > limit)
-                || pod.metadata.labels.as_ref().map_or(false, |labels| {
-                    labels.get(LABEL_KEY).map(|l| l.as_str()) == Some(LABEL_DELETE)
-                })
+                .and_then(|a| a.get(HEARTBEAT_ANNOTATION))
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok());
+            let stale = match heartbeat {
+                // A pod with a recent heartbeat has a live session: it is not stale.
+                Some(t) => now - t.with_timezone(&chrono::Utc) > heartbeat_limit,
+                // No heartbeat yet (older pod, or still starting up): fall back to age.
+                None => pod
+                    .metadata
+                    .creation_timestamp
+                    .as_ref()
+                    .map_or(false, |t| now - t.0 > limit),
+            };
+            marked_for_delete || stale
         });
         info!("Found {} pods to delete", pods_list.len());
         for p in pods_list {
@@ -87,9 +140,31 @@ async fn main_impl() -> anyhow::Result<()> {
             let name = p.metadata.name.unwrap();
             api.delete(&name, &Default::default()).await?;
             if !args.nowait {
-                await_condition(api.clone(), &name, is_deleted(&p.metadata.uid.unwrap())).await?;
+                wait_deleted(&api, &name, &p.metadata.uid.unwrap(), *args.delete_timeout).await?;
             }
         }
+        let pvcs: Api<PersistentVolumeClaim> = Api::all(client.clone());
+        let mut pvcs_list = pvcs
+            .list_metadata(&ListParams::default().labels(LABEL_KEY))
+            .await?
+            .items;
+        pvcs_list.retain(|pvc| {
+            pvc.metadata
+                .creation_timestamp
+                .as_ref()
+                .map_or(false, |t| now - t.0 > limit)
+                || pvc.metadata.labels.as_ref().map_or(false, |labels| {
+                    labels.get(LABEL_KEY).map(|l| l.as_str()) == Some(LABEL_DELETE)
+                })
+        });
+        info!("Found {} clone PVCs to delete", pvcs_list.len());
+        for p in pvcs_list {
+            let api: Api<PersistentVolumeClaim> =
+                Api::namespaced(client.clone(), &p.metadata.namespace.unwrap());
+            api.delete(&p.metadata.name.unwrap(), &Default::default())
+                .await?;
+        }
+
         info!("Done");
         return Ok(());
     }
@@ -97,20 +172,59 @@ async fn main_impl() -> anyhow::Result<()> {
     let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), &args.namespace);
 
     let pvcs_list = pvcs.list(&Default::default()).await?;
-    if let Some(name) = args.name {
+    let claims: Vec<(String, PathBuf)> = if !args.mounts.is_empty() {
+        anyhow::ensure!(
+            args.names.is_empty(),
+            "Provide PVC names either positionally or via --mount, not both"
+        );
+        args.mounts.clone()
+    } else if args.names.len() == 1 {
+        vec![(args.names[0].clone(), PathBuf::from("/data"))]
+    } else {
+        args.names
+            .iter()
+            .map(|name| (name.clone(), PathBuf::from(format!("/data/{name}"))))
+            .collect()
+    };
+    if !claims.is_empty() {
         let read_only = Some(!args.rw);
         if args.rw {
-            warn!("Volume will be mounted in read/write mode");
+            warn!("Volume(s) will be mounted in read/write mode");
+        }
+        for (name, _) in &claims {
+            anyhow::ensure!(
+                pvcs_list
+                    .iter()
+                    .any(|pvc| pvc.metadata().name.as_deref() == Some(name.as_str())),
+                "PVC {} not found",
+                name
+            );
         }
+
+        let do_clone = args.clone || args.from_snapshot.is_some();
         anyhow::ensure!(
-            pvcs_list.into_iter().any(|pvc| pvc
-                .metadata()
-                .name
-                .as_ref()
-                .map_or(false, |n| n == &name)),
-            "PVC {} not found",
-            name
+            !(args.from_snapshot.is_some() && claims.len() > 1),
+            "--from-snapshot can only be used with a single PVC"
         );
+        anyhow::ensure!(
+            !(args.export.is_some() && claims.len() > 1),
+            "--export only supports a single PVC at a time"
+        );
+        let mut claim_names = Vec::with_capacity(claims.len());
+        for (name, _) in &claims {
+            let claim_name = if do_clone {
+                create_clone_pvc(&pvcs, &args.namespace, name, args.from_snapshot.as_deref())
+                    .await?
+            } else {
+                name.clone()
+            };
+            claim_names.push(claim_name);
+        }
+        let clone_pvc_names = if do_clone {
+            claim_names.clone()
+        } else {
+            Vec::new()
+        };
 
         info!("Generating keys");
         let key = ssh_key::PrivateKey::random(
@@ -123,13 +237,19 @@ async fn main_impl() -> anyhow::Result<()> {
         info!("Creating pod");
         let yaml = include_str!("../templates/ssh.yaml");
 
-        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
         let port = listener.local_addr()?.port();
-        drop(listener);
 
         let mut pod: Pod = serde_yaml::from_str(yaml)?;
         pod.metadata = ObjectMeta {
-            generate_name: Some(format!("pvc-inspect-{}-", name)),
+            generate_name: Some(format!(
+                "pvc-inspect-{}-",
+                claims
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("-")
+            )),
             namespace: Some(args.namespace.clone()),
             labels: Some([(LABEL_KEY.into(), "1".into())].into()),
             ..Default::default()
@@ -137,14 +257,16 @@ async fn main_impl() -> anyhow::Result<()> {
         let spec = pod.spec.get_or_insert(Default::default());
 
         let volumes = spec.volumes.get_or_insert(Default::default());
-        volumes.push(Volume {
-            name: "data".into(),
-            persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
-                claim_name: name,
-                read_only,
-            }),
-            ..Default::default()
-        });
+        for (i, claim_name) in claim_names.iter().enumerate() {
+            volumes.push(Volume {
+                name: format!("data-{i}"),
+                persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
+                    claim_name: claim_name.clone(),
+                    read_only,
+                }),
+                ..Default::default()
+            });
+        }
 
         for container in &mut spec.containers {
             let env = container.env.get_or_insert(Default::default());
@@ -154,12 +276,14 @@ async fn main_impl() -> anyhow::Result<()> {
                 ..Default::default()
             });
             let mounts = container.volume_mounts.get_or_insert(Default::default());
-            mounts.push(VolumeMount {
-                mount_path: "/data".into(),
-                name: "data".into(),
-                read_only,
-                ..Default::default()
-            });
+            for (i, (_, path)) in claims.iter().enumerate() {
+                mounts.push(VolumeMount {
+                    mount_path: path.to_string_lossy().into_owned(),
+                    name: format!("data-{i}"),
+                    read_only,
+                    ..Default::default()
+                });
+            }
         }
         let pods: Api<Pod> = Api::namespaced(client, &args.namespace);
         let pod = pods.create(&Default::default(), &pod).await?;
@@ -185,111 +309,155 @@ async fn main_impl() -> anyhow::Result<()> {
             }
         }
 
-        await_condition(pods.clone(), &pod_name, PodReady {}).await?;
+        match tokio::time::timeout(
+            *args.ready_timeout,
+            await_condition(pods.clone(), &pod_name, PodReady {}),
+        )
+        .await
+        {
+            Ok(result) => {
+                result?;
+            }
+            Err(_) => {
+                anyhow::bail!(
+                    "Timed out after {} waiting for pod {} to become ready: {}",
+                    args.ready_timeout,
+                    pod_name,
+                    describe_pod_status(&pods, &pod_name).await
+                );
+            }
+        }
         std::thread::sleep(std::time::Duration::from_secs(1));
 
         info!("Pod created");
-        info!("Starting port forwarding on port {}", port);
-        // TODO: We could do this with Kube directly
-        let mut forward = std::process::Command::new("kubectl")
-            .args([
-                "-n",
-                &args.namespace,
-                "port-forward",
-                &pod_name,
-                &format!("{}:2222", port),
-            ])
-            .stdout(std::process::Stdio::piped())
-            .spawn()?;
-        let stdout = forward.stdout.take().unwrap();
-        let mut stdout = std::io::BufReader::new(stdout);
-        let mut line = String::new();
-        stdout.read_line(&mut line)?;
-
-        let mount = if let Some(mountpoint) = args.mountpoint {
-            info!("Mounting on {:?}", mountpoint);
-            std::fs::create_dir_all(&mountpoint)?;
-            let child = std::process::Command::new("sshfs")
-                .args([
-                    "ssh@127.0.0.1:/data",
-                    "-o",
-                    "auto_unmount",
-                    "-o",
-                    "UserKnownHostsFile=/dev/null",
-                    "-o",
-                    &format!("IdentityFile={}", key_file.path().to_str().unwrap()),
-                    "-o",
-                    "StrictHostKeyChecking=no",
-                    "-f",
-                    "-p",
-                    &port.to_string(),
-                    mountpoint.to_str().unwrap(),
-                ])
-                .stderr(std::process::Stdio::null())
-                .stdout(std::process::Stdio::null())
-                .spawn();
-            match child {
-                Ok(child) => Some(child),
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    anyhow::bail!("`sshfs` not found in PATH.")
-                }
-                Err(e) => {
-                    return Err(e).context("Failed to mount via SSHFS");
+        let heartbeat_pods = pods.clone();
+        let heartbeat_pod_name = pod_name.clone();
+        let heartbeat = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = patch_heartbeat(&heartbeat_pods, &heartbeat_pod_name).await {
+                    error!("Failed to patch heartbeat on {}: {}", heartbeat_pod_name, e);
                 }
             }
-        } else {
-            None
-        };
-
-        info!("Connecting to pod. Type Control+D to exit the shell");
-        // As in kube/examples/pod_shell_crossterm.rs
-        let mut exec = pods
-            .exec(
-                &pod_name,
-                ["/bin/bash", "-c", "cd /data && /bin/bash"],
-                &AttachParams::interactive_tty(),
-            )
-            .await?;
-        crossterm::terminal::enable_raw_mode()?;
-        let mut stdin = tokio_util::io::ReaderStream::new(tokio::io::stdin());
-        let mut stdout = tokio::io::stdout();
-        let mut output = tokio_util::io::ReaderStream::new(exec.stdout().unwrap());
-        let mut input = exec.stdin().unwrap();
-        loop {
-            tokio::select! {
-                message = stdin.next() => {
-                    match message {
-                        Some(Ok(message)) => {
-                            let _ = input.write(&message).await?;
-                        }
-                        _ => {
-                            break;
-                        },
+        });
+        info!("Starting port forwarding on port {}", port);
+        let forward_pods = pods.clone();
+        let forward_pod_name = pod_name.clone();
+        let forward = tokio::spawn(async move {
+            loop {
+                let (conn, _peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Failed to accept port-forward connection: {}", e);
+                        continue;
                     }
-                },
-                message = output.next() => {
-                    match message {
-                        Some(Ok(message)) => {
-                            let _ = stdout.write(&message).await?;
-                            stdout.flush().await?;
-                        },
-                        _ => {
-                            break
-                        },
+                };
+                let pods = forward_pods.clone();
+                let pod_name = forward_pod_name.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward_connection(&pods, &pod_name, conn).await {
+                        error!("Port forwarding to {} failed: {}", pod_name, e);
                     }
-                },
+                });
+            }
+        });
+
+        // Where to `cd` for the interactive shell / export: /data if it is one of the mounted
+        // paths, otherwise the first claim's mountpoint.
+        let cwd = claims
+            .iter()
+            .find(|(_, path)| path == Path::new("/data"))
+            .or_else(|| claims.first())
+            .map(|(_, path)| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "/data".into());
+
+        if let Some(export_dest) = &args.export {
+            run_export(&pods, &pod_name, &cwd, export_dest, args.s3_endpoint.as_deref()).await?;
+        } else {
+            let mounts = if let Some(mountpoint) = &args.mountpoint {
+                info!("Mounting on {:?}", mountpoint);
+                std::fs::create_dir_all(mountpoint)?;
+                if claims.len() == 1 {
+                    vec![spawn_sshfs(&cwd, mountpoint, port, key_file.path())?]
+                } else {
+                    claims
+                        .iter()
+                        .map(|(name, remote_path)| {
+                            let local_path = mountpoint.join(name);
+                            std::fs::create_dir_all(&local_path)?;
+                            spawn_sshfs(
+                                &remote_path.to_string_lossy(),
+                                &local_path,
+                                port,
+                                key_file.path(),
+                            )
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?
+                }
+            } else {
+                Vec::new()
             };
+
+            info!("Connecting to pod. Type Control+D to exit the shell");
+            // As in kube/examples/pod_shell_crossterm.rs
+            let mut exec = pods
+                .exec(
+                    &pod_name,
+                    ["/bin/bash", "-c", &format!("cd {cwd} && /bin/bash")],
+                    &AttachParams::interactive_tty(),
+                )
+                .await?;
+            crossterm::terminal::enable_raw_mode()?;
+            let mut stdin = tokio_util::io::ReaderStream::new(tokio::io::stdin());
+            let mut stdout = tokio::io::stdout();
+            let mut output = tokio_util::io::ReaderStream::new(exec.stdout().unwrap());
+            let mut input = exec.stdin().unwrap();
+            loop {
+                tokio::select! {
+                    message = stdin.next() => {
+                        match message {
+                            Some(Ok(message)) => {
+                                let _ = input.write(&message).await?;
+                            }
+                            _ => {
+                                break;
+                            },
+                        }
+                    },
+                    message = output.next() => {
+                        match message {
+                            Some(Ok(message)) => {
+                                let _ = stdout.write(&message).await?;
+                                stdout.flush().await?;
+                            },
+                            _ => {
+                                break
+                            },
+                        }
+                    },
+                };
+            }
+            crossterm::terminal::disable_raw_mode()?;
+
+            if !mounts.is_empty() {
+                info!("Unmounting");
+                for mut mount in mounts {
+                    mount.kill()?;
+                }
+            }
         }
-        crossterm::terminal::disable_raw_mode()?;
 
         // Cleanup
 
-        if let Some(mut mount) = mount {
-            info!("Unmounting");
-            mount.kill()?;
-        }
+        info!("Stopping heartbeat");
+        heartbeat.abort();
         info!("Stopping port forwarding");
-        forward.kill()?;
+        forward.abort();
+        for clone_pvc_name in &clone_pvc_names {
+            info!("Deleting temporary clone PVC {}", clone_pvc_name);
+            pvcs.delete(clone_pvc_name, &Default::default()).await?;
+        }
         info!("Deleting pod");
         // Edit the label to mark the pod for deletion, to cover the use case where the user might
         // not have the right to delete pods
@@ -307,10 +475,11 @@ async fn main_impl() -> anyhow::Result<()> {
         pods.delete(&pod_name, &Default::default()).await?;
         if !args.nowait {
             info!("Waiting for deletion");
-            await_condition(
-                pods.clone(),
+            wait_deleted(
+                &pods,
                 &pod_name,
-                is_deleted(&pod.metadata.uid.unwrap()),
+                &pod.metadata.uid.unwrap(),
+                *args.delete_timeout,
             )
             .await?;
             info!("Pod deleted");
@@ -346,6 +515,352 @@ async fn main_impl() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Provision a temporary PVC cloned from `source_name` (or from the `from_snapshot` VolumeSnapshot,
+/// if given), copying its access modes and requested storage size. Returns the name of the clone.
+async fn create_clone_pvc(
+    pvcs: &Api<PersistentVolumeClaim>,
+    namespace: &str,
+    source_name: &str,
+    from_snapshot: Option<&str>,
+) -> anyhow::Result<String> {
+    let source = pvcs.get(source_name).await?;
+    let source_spec = source.spec.context("source PVC has no spec")?;
+    let data_source = match from_snapshot {
+        Some(snapshot) => {
+            info!("Cloning PVC {} from snapshot {}", source_name, snapshot);
+            TypedLocalObjectReference {
+                api_group: Some("snapshot.storage.k8s.io".into()),
+                kind: "VolumeSnapshot".into(),
+                name: snapshot.into(),
+            }
+        }
+        None => {
+            info!("Cloning PVC {}", source_name);
+            TypedLocalObjectReference {
+                api_group: None,
+                kind: "PersistentVolumeClaim".into(),
+                name: source_name.into(),
+            }
+        }
+    };
+    let clone = PersistentVolumeClaim {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("pvc-inspect-clone-{}-", source_name)),
+            namespace: Some(namespace.into()),
+            labels: Some([(LABEL_KEY.into(), "1".into())].into()),
+            ..Default::default()
+        },
+        spec: Some(PersistentVolumeClaimSpec {
+            access_modes: source_spec.access_modes,
+            resources: source_spec.resources,
+            data_source: Some(data_source),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let clone = pvcs.create(&Default::default(), &clone).await?;
+    let name = clone.metadata.name.context("created PVC has no name")?;
+    info!("Created clone PVC {}", name);
+    Ok(name)
+}
+
+/// Mount `remote_path` (on the pod, via the SSH server forwarded to `port`) at `local_path` using
+/// SSHFS.
+fn spawn_sshfs(
+    remote_path: &str,
+    local_path: &Path,
+    port: u16,
+    identity_file: &Path,
+) -> anyhow::Result<std::process::Child> {
+    let child = std::process::Command::new("sshfs")
+        .args([
+            &format!("ssh@127.0.0.1:{remote_path}"),
+            "-o",
+            "auto_unmount",
+            "-o",
+            "UserKnownHostsFile=/dev/null",
+            "-o",
+            &format!("IdentityFile={}", identity_file.to_str().unwrap()),
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-f",
+            "-p",
+            &port.to_string(),
+            local_path.to_str().unwrap(),
+        ])
+        .stderr(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .spawn();
+    match child {
+        Ok(child) => Ok(child),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("`sshfs` not found in PATH.")
+        }
+        Err(e) => Err(e).context("Failed to mount via SSHFS"),
+    }
+}
+
+/// Exec `tar czf - -C {path} .` in `pod_name` and stream the result to `dest`, which is either a
+/// local file path or an `s3://bucket/key` URL.
+async fn run_export(
+    pods: &Api<Pod>,
+    pod_name: &str,
+    path: &str,
+    dest: &str,
+    s3_endpoint: Option<&str>,
+) -> anyhow::Result<()> {
+    info!("Exporting {} to {}", path, dest);
+    let mut exec = pods
+        .exec(
+            pod_name,
+            ["tar", "czf", "-", "-C", path, "."],
+            &AttachParams::default().stdout(true).stderr(true),
+        )
+        .await?;
+    let stream = tokio_util::io::ReaderStream::new(exec.stdout().context("no stdout on exec")?);
+    let mut stderr = exec.stderr().context("no stderr on exec")?;
+
+    let copy = async {
+        if let Some(rest) = dest.strip_prefix("s3://") {
+            let (bucket, key) = rest
+                .split_once('/')
+                .context("S3 destination must be of the form s3://bucket/key")?;
+            upload_to_s3(bucket, key, s3_endpoint, stream).await
+        } else {
+            let mut file = tokio::fs::File::create(dest).await?;
+            let mut reader = tokio_util::io::StreamReader::new(stream);
+            tokio::io::copy(&mut reader, &mut file).await?;
+            Ok(())
+        }
+    };
+    copy.await?;
+
+    let mut stderr_buf = Vec::new();
+    stderr.read_to_end(&mut stderr_buf).await.ok();
+
+    let status = exec.take_status();
+    exec.join().await?;
+    let status = match status {
+        Some(fut) => fut.await,
+        None => None,
+    };
+    anyhow::ensure!(
+        status.as_ref().and_then(|s| s.status.as_deref()) == Some("Success"),
+        "tar failed in pod {}: {}",
+        pod_name,
+        String::from_utf8_lossy(&stderr_buf).trim()
+    );
+    info!("Export complete");
+    Ok(())
+}
+
+/// Stream `body` to `bucket`/`key` via a streaming S3 multipart upload, so the whole tarball
+/// never needs to fit in memory. `endpoint` overrides the default AWS endpoint resolution, for
+/// use against S3-compatible stores (MinIO, etc).
+async fn upload_to_s3(
+    bucket: &str,
+    key: &str,
+    endpoint: Option<&str>,
+    mut body: impl futures::Stream<Item = std::io::Result<bytes::Bytes>> + Unpin,
+) -> anyhow::Result<()> {
+    let mut loader = aws_config::from_env();
+    if let Some(endpoint) = endpoint {
+        loader = loader.endpoint_url(endpoint);
+    }
+    let client = aws_sdk_s3::Client::new(&loader.load().await);
+
+    let multipart = client
+        .create_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await?;
+    let upload_id = multipart.upload_id().context("no upload id returned")?;
+
+    if let Err(e) = upload_parts_and_complete(&client, bucket, key, upload_id, &mut body).await {
+        if let Err(abort_err) = client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            warn!("Failed to abort multipart upload {upload_id}: {abort_err}");
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Upload `body` as a sequence of parts and complete the multipart upload `upload_id`, so that
+/// [`upload_to_s3`] can abort it on any error without duplicating the upload loop.
+async fn upload_parts_and_complete(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    body: &mut (impl futures::Stream<Item = std::io::Result<bytes::Bytes>> + Unpin),
+) -> anyhow::Result<()> {
+    const PART_SIZE: usize = 8 * 1024 * 1024;
+    let mut buffer = Vec::with_capacity(PART_SIZE);
+    let mut parts = Vec::new();
+    let mut part_number = 1;
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk?);
+        if buffer.len() >= PART_SIZE {
+            parts.push(upload_part(client, bucket, key, upload_id, part_number, &buffer).await?);
+            buffer.clear();
+            part_number += 1;
+        }
+    }
+    if !buffer.is_empty() {
+        parts.push(upload_part(client, bucket, key, upload_id, part_number, &buffer).await?);
+    }
+
+    client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            aws_sdk_s3::types::CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn upload_part(
+    client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_number: i32,
+    data: &[u8],
+) -> anyhow::Result<aws_sdk_s3::types::CompletedPart> {
+    let output = client
+        .upload_part()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .part_number(part_number)
+        .body(aws_sdk_s3::primitives::ByteStream::from(data.to_vec()))
+        .send()
+        .await?;
+    Ok(aws_sdk_s3::types::CompletedPart::builder()
+        .set_e_tag(output.e_tag().map(String::from))
+        .part_number(part_number)
+        .build())
+}
+
+/// Patch `pod_name` with a fresh [`HEARTBEAT_ANNOTATION`], so `--cleanup` knows this pod still
+/// has a live session.
+async fn patch_heartbeat(pods: &Api<Pod>, pod_name: &str) -> anyhow::Result<()> {
+    let metadata = ObjectMeta {
+        annotations: Some([(HEARTBEAT_ANNOTATION.into(), chrono::Utc::now().to_rfc3339())].into()),
+        ..Default::default()
+    }
+    .into_request_partial::<Pod>();
+    pods.patch_metadata(
+        pod_name,
+        &kube::api::PatchParams::apply("pv_inspect").force(),
+        &kube::api::Patch::Apply(metadata),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Wait for `name` to be deleted, bailing out with a diagnostic if it takes longer than `timeout`.
+async fn wait_deleted(
+    pods: &Api<Pod>,
+    name: &str,
+    uid: &str,
+    timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    match tokio::time::timeout(timeout, await_condition(pods.clone(), name, is_deleted(uid))).await
+    {
+        Ok(result) => {
+            result?;
+            Ok(())
+        }
+        Err(_) => anyhow::bail!(
+            "Timed out after {} waiting for pod {} to be deleted: {}",
+            humantime::format_duration(timeout),
+            name,
+            describe_pod_deletion(pods, name).await
+        ),
+    }
+}
+
+/// Fetch the latest metadata of `name` and render it as a short diagnostic (deletion timestamp,
+/// remaining finalizers), for use when a deletion wait times out. Unlike [`describe_pod_status`],
+/// this tolerates the pod having already disappeared by the time we look.
+async fn describe_pod_deletion(pods: &Api<Pod>, name: &str) -> String {
+    match pods.get_metadata(name).await {
+        Ok(pod) => {
+            let deletion_timestamp = pod
+                .metadata
+                .deletion_timestamp
+                .map_or("none".to_string(), |t| t.0.to_rfc3339());
+            let finalizers = pod
+                .metadata
+                .finalizers
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("deletionTimestamp={deletion_timestamp} finalizers=[{finalizers}]")
+        }
+        Err(kube::Error::Api(e)) if e.code == 404 => {
+            "pod no longer exists, but the delete watch never observed it disappearing".into()
+        }
+        Err(e) => format!("failed to fetch pod metadata: {e}"),
+    }
+}
+
+/// Fetch the latest status of `name` and render it as a short diagnostic (container states), for
+/// use when a wait times out.
+async fn describe_pod_status(pods: &Api<Pod>, name: &str) -> String {
+    match pods.get(name).await {
+        Ok(pod) => match pod.status {
+            Some(status) => {
+                let phase = status.phase.as_deref().unwrap_or("unknown");
+                let containers = status
+                    .container_statuses
+                    .into_iter()
+                    .flatten()
+                    .map(|cs| format!("{}: ready={} state={:?}", cs.name, cs.ready, cs.state))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("phase={phase} containers=[{containers}]")
+            }
+            None => "no status reported".into(),
+        },
+        Err(e) => format!("failed to fetch pod status: {e}"),
+    }
+}
+
+/// Forward a single local connection to port 2222 on `pod_name`, copying bytes bidirectionally
+/// until either side closes the connection.
+async fn forward_connection(
+    pods: &Api<Pod>,
+    pod_name: &str,
+    mut client_conn: impl AsyncRead + AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let port = 2222;
+    let mut forwarder = pods.portforward(pod_name, &[port]).await?;
+    let mut upstream_conn = forwarder
+        .take_stream(port)
+        .context("port not found in forwarder")?;
+    tokio::io::copy_bidirectional(&mut client_conn, &mut upstream_conn).await?;
+    drop(upstream_conn);
+    forwarder.join().await?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     if let Err(e) = main_impl().await {